@@ -1,25 +1,191 @@
+use std::any::Any;
 use std::io::{self, Read, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::channel;
+
 use crc::{Hasher32, crc32};
 use byteorder::{LittleEndian, WriteBytesExt};
+use threadpool::ThreadPool;
 
 use deflate::{deflate, BlockType};
 use Options;
 use iter_read::IterRead;
 
-static HEADER: &'static [u8] = &[
-    31,  // ID1
-    139, // ID2
-    8,   // CM
-    0,   // FLG
+const FLG_FTEXT: u8 = 0x01;
+const FLG_FHCRC: u8 = 0x02;
+const FLG_FEXTRA: u8 = 0x04;
+const FLG_FNAME: u8 = 0x08;
+const FLG_FCOMMENT: u8 = 0x10;
 
-    0,   // MTIME
-    0,
-    0,
-    0,
+/// The gzip header fields described by RFC 1952, with a chainable builder
+/// API so callers can opt into the optional fields without touching the
+/// hardcoded defaults.
+#[derive(Clone, Debug)]
+pub struct GzipHeader {
+    mtime: u32,
+    os: u8,
+    text: bool,
+    hcrc: bool,
+    extra: Option<Vec<u8>>,
+    filename: Option<String>,
+    comment: Option<String>,
+}
 
-    2,   // XFL, 2 indicates best compression.
-    3,   // OS follows Unix conventions.
-];
+impl Default for GzipHeader {
+    fn default() -> GzipHeader {
+        GzipHeader {
+            mtime: 0,
+            os: 3, // Unix
+            text: false,
+            hcrc: false,
+            extra: None,
+            filename: None,
+            comment: None,
+        }
+    }
+}
+
+impl GzipHeader {
+    /// Sets MTIME, the modification time of the original file, as a Unix
+    /// timestamp. 0 means unknown/not available.
+    pub fn mtime(mut self, mtime: u32) -> GzipHeader {
+        self.mtime = mtime;
+        self
+    }
+
+    /// Sets the OS byte. 3 (the default) indicates Unix.
+    pub fn os(mut self, os: u8) -> GzipHeader {
+        self.os = os;
+        self
+    }
+
+    /// Sets the FTEXT flag, which hints that the compressed data is
+    /// probably ASCII text.
+    pub fn text(mut self, text: bool) -> GzipHeader {
+        self.text = text;
+        self
+    }
+
+    /// Enables FHCRC: a CRC16 of all header bytes preceding it is written
+    /// just before the deflate payload.
+    pub fn hcrc(mut self, hcrc: bool) -> GzipHeader {
+        self.hcrc = hcrc;
+        self
+    }
+
+    /// Sets the raw FEXTRA subfield bytes. Fails if `extra` is longer than
+    /// 65535 bytes, since XLEN is a 16-bit field and a longer subfield
+    /// would silently wrap instead of being written out in full.
+    pub fn extra(mut self, extra: Vec<u8>) -> io::Result<GzipHeader> {
+        if extra.len() > u16::max_value() as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("FEXTRA subfield is {} bytes, which overflows the 16-bit XLEN field", extra.len()),
+            ));
+        }
+        self.extra = Some(extra);
+        Ok(self)
+    }
+
+    /// Sets FNAME, the original filename, stored as a NUL-terminated
+    /// Latin-1 string. Fails if `filename` contains a NUL or a character
+    /// outside Latin-1 (U+0001..=U+00FF), since either would corrupt the
+    /// field or whatever follows it in the header.
+    pub fn filename<S: Into<String>>(mut self, filename: S) -> io::Result<GzipHeader> {
+        let filename = filename.into();
+        try!(validate_latin1(&filename));
+        self.filename = Some(filename);
+        Ok(self)
+    }
+
+    /// Sets FCOMMENT, a free-form comment, stored as a NUL-terminated
+    /// Latin-1 string. Fails under the same conditions as `filename`.
+    pub fn comment<S: Into<String>>(mut self, comment: S) -> io::Result<GzipHeader> {
+        let comment = comment.into();
+        try!(validate_latin1(&comment));
+        self.comment = Some(comment);
+        Ok(self)
+    }
+}
+
+/// Checks that `s` contains only characters that survive a lossless
+/// NUL-terminated Latin-1 round trip: no NUL (which would truncate the
+/// field early) and no codepoint above U+00FF (which doesn't have a
+/// single-byte Latin-1 representation).
+fn validate_latin1(s: &str) -> io::Result<()> {
+    for c in s.chars() {
+        if c == '\0' || c as u32 > 0xFF {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{:?} is not a valid NUL-terminated Latin-1 string: contains {:?}", s, c),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Appends `s` to `buf` as a NUL-terminated Latin-1 string. Callers must
+/// have already validated `s` with `validate_latin1`.
+fn push_latin1_nul_terminated(buf: &mut Vec<u8>, s: &str) {
+    for c in s.chars() {
+        buf.push(c as u32 as u8);
+    }
+    buf.push(0);
+}
+
+fn header_bytes(header: &GzipHeader) -> Vec<u8> {
+    let mut flg = 0u8;
+    if header.text {
+        flg |= FLG_FTEXT;
+    }
+    if header.hcrc {
+        flg |= FLG_FHCRC;
+    }
+    if header.extra.is_some() {
+        flg |= FLG_FEXTRA;
+    }
+    if header.filename.is_some() {
+        flg |= FLG_FNAME;
+    }
+    if header.comment.is_some() {
+        flg |= FLG_FCOMMENT;
+    }
+
+    let mut buf = Vec::new();
+    buf.push(31);  // ID1
+    buf.push(139); // ID2
+    buf.push(8);   // CM
+    buf.push(flg); // FLG
+
+    buf.write_u32::<LittleEndian>(header.mtime).expect("writing to a Vec<u8> cannot fail");
+
+    buf.push(2); // XFL, 2 indicates best compression.
+    buf.push(header.os);
+
+    if let Some(ref extra) = header.extra {
+        // GzipHeader::extra already rejected lengths that don't fit XLEN.
+        let xlen = extra.len() as u16;
+        buf.write_u16::<LittleEndian>(xlen).expect("writing to a Vec<u8> cannot fail");
+        buf.extend_from_slice(extra);
+    }
+
+    if let Some(ref filename) = header.filename {
+        push_latin1_nul_terminated(&mut buf, filename);
+    }
+
+    if let Some(ref comment) = header.comment {
+        push_latin1_nul_terminated(&mut buf, comment);
+    }
+
+    if header.hcrc {
+        let mut digest = crc32::Digest::new(crc32::IEEE);
+        digest.write(&buf);
+        let crc16 = digest.sum32() as u16;
+        buf.write_u16::<LittleEndian>(crc16).expect("writing to a Vec<u8> cannot fail");
+    }
+
+    buf
+}
 
 /// Compresses the data according to the gzip specification, RFC 1952.
 pub fn gzip_compress<R, W>(options: &Options, in_data: R, insize: u64, mut out: W) -> io::Result<()>
@@ -40,7 +206,7 @@ pub fn gzip_compress<R, W>(options: &Options, in_data: R, insize: u64, mut out:
         byte_result.ok()
     }).fuse());
 
-    try!(out.by_ref().write_all(HEADER));
+    try!(out.by_ref().write_all(&header_bytes(&options.gzip_header)));
 
     try!(deflate(options, BlockType::Dynamic, in_data, insize, out.by_ref()));
 
@@ -55,3 +221,341 @@ pub fn gzip_compress<R, W>(options: &Options, in_data: R, insize: u64, mut out:
 
     out.write_u32::<LittleEndian>(insize as u32)
 }
+
+/// Extracts a human-readable message from a caught panic payload, falling
+/// back to a generic message for payloads that aren't `&str` or `String`
+/// (the types `panic!` and friends normally produce).
+fn panic_message(panic: &Box<Any + Send>) -> String {
+    if let Some(message) = panic.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = panic.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown panic".to_string()
+    }
+}
+
+/// Compresses `in_data` as a sequence of independent gzip members, one per
+/// `options.chunk_size`-sized chunk, spread across a thread pool sized to
+/// the CPU count. The members are written to `out` in input order.
+///
+/// Each member carries its own header, CRC32 and ISIZE trailer, so the
+/// concatenated output is a single valid `.gz` stream per RFC 1952 with no
+/// shared LZ77 state needed between chunks.
+pub fn gzip_compress_parallel<W>(options: &Options, in_data: &[u8], mut out: W) -> io::Result<()>
+    where W: Write
+{
+    let chunk_size = if options.chunk_size == 0 { 1 } else { options.chunk_size as usize };
+    let chunks: Vec<Vec<u8>> = if in_data.is_empty() {
+        vec![Vec::new()]
+    } else {
+        in_data.chunks(chunk_size).map(|chunk| chunk.to_vec()).collect()
+    };
+    let num_chunks = chunks.len();
+
+    let pool = ThreadPool::new(num_cpus::get());
+    let (tx, rx) = channel();
+
+    for (index, chunk) in chunks.into_iter().enumerate() {
+        let tx = tx.clone();
+        let chunk_options = options.clone();
+        pool.execute(move || {
+            // Caught so a panicking worker (e.g. from a degenerate Options)
+            // always sends a result instead of leaving the receiver below
+            // waiting forever for a message that will never arrive.
+            let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                #[cfg(test)]
+                fault_injection::maybe_panic(index);
+
+                let mut member = Vec::new();
+                gzip_compress(&chunk_options, &chunk[..], chunk.len() as u64, &mut member)
+                    .map(|_| member)
+            })).unwrap_or_else(|panic| Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("gzip compression worker panicked: {}", panic_message(&panic)),
+            )));
+            // The receiving end outlives every sender, so this can only
+            // fail if the pool is being torn down; nothing useful to do.
+            let _ = tx.send((index, result));
+        });
+    }
+    drop(tx);
+
+    let mut members: Vec<Option<io::Result<Vec<u8>>>> = (0..num_chunks).map(|_| None).collect();
+    for (index, result) in rx.iter().take(num_chunks) {
+        members[index] = Some(result);
+    }
+
+    for member in members {
+        let bytes = try!(member.expect("thread pool dropped a chunk result"));
+        try!(out.write_all(&bytes));
+    }
+
+    Ok(())
+}
+
+/// Maximum uncompressed size of a single BGZF block. The BGZF spec caps the
+/// total (compressed) block length at 65536 bytes via the 16-bit BSIZE
+/// field; 0xff00 (65280), the value real bgzip implementations use, leaves
+/// enough headroom for the header, trailer and deflate's worst-case
+/// expansion on incompressible input without BSIZE overflowing.
+const BGZF_MAX_BLOCK_SIZE: usize = 0xff00;
+
+/// The 28-byte empty BGZF block that terminates every BGZF stream.
+static BGZF_EOF: &'static [u8] = &[
+    0x1f, 0x8b, 0x08, 0x04, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0xff, 0x06, 0x00, 0x42, 0x43, 0x02, 0x00,
+    0x1b, 0x00, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00,
+];
+
+/// Computes a BGZF virtual file offset: the compressed offset of a block's
+/// start shifted left 16 bits, OR'd with the uncompressed offset within
+/// that block. Callers can use these to build a companion index for
+/// random-access seeking into a BGZF stream.
+pub fn bgzf_virtual_offset(compressed_offset: u64, uncompressed_offset: u16) -> u64 {
+    (compressed_offset << 16) | uncompressed_offset as u64
+}
+
+/// Builds the BGZF FEXTRA subfield: SI1='B', SI2='C', SLEN=2, followed by
+/// the 2-byte BSIZE value itself.
+fn bgzf_extra_subfield(bsize: u16) -> Vec<u8> {
+    let mut extra = vec![b'B', b'C'];
+    extra.write_u16::<LittleEndian>(2).expect("writing to a Vec<u8> cannot fail"); // SLEN
+    extra.write_u16::<LittleEndian>(bsize).expect("writing to a Vec<u8> cannot fail");
+    extra
+}
+
+/// Compresses one chunk as a single, self-contained BGZF block: a gzip
+/// member whose FEXTRA subfield advertises the total block length via
+/// SI1='B', SI2='C', SLEN=2, BSIZE = block length - 1. Returns the number
+/// of bytes written.
+///
+/// Any FEXTRA subfield already set on `options.gzip_header` is replaced by
+/// the BC subfield, not appended to, since BSIZE must be the gzip member's
+/// only extra field for BGZF-aware readers to find it at a fixed offset.
+fn write_bgzf_block<W>(options: &Options, chunk: &[u8], mut out: W) -> io::Result<usize>
+    where W: Write
+{
+    let mut payload = Vec::new();
+    try!(deflate(options, BlockType::Dynamic, chunk, chunk.len() as u64, &mut payload));
+
+    let mut digest = crc32::Digest::new(crc32::IEEE);
+    digest.write(chunk);
+
+    // Placeholder BSIZE so we can measure the header's exact length, which
+    // doesn't depend on BSIZE's value, only on the rest of the options.
+    let header = try!(options.gzip_header.clone().extra(bgzf_extra_subfield(0)));
+    let header_len = header_bytes(&header).len();
+
+    let total_len = header_len + payload.len() + 8; // + CRC32 + ISIZE
+    if total_len - 1 > u16::max_value() as usize {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("BGZF block length {} overflows the 16-bit BSIZE field", total_len),
+        ));
+    }
+    let bsize = (total_len - 1) as u16;
+
+    let header = try!(options.gzip_header.clone().extra(bgzf_extra_subfield(bsize)));
+    let header = header_bytes(&header);
+    try!(out.write_all(&header));
+    try!(out.write_all(&payload));
+    try!(out.write_u32::<LittleEndian>(digest.sum32()));
+    try!(out.write_u32::<LittleEndian>(chunk.len() as u32));
+
+    Ok(header.len() + payload.len() + 8)
+}
+
+/// Compresses `in_data` to BGZF (Blocked GZip Format): a sequence of
+/// independent gzip members, each holding at most `BGZF_MAX_BLOCK_SIZE`
+/// bytes of uncompressed data, terminated by the standard empty EOF block.
+/// Unlike plain multi-member gzip, every block's FEXTRA field records its own
+/// length, which is what lets BGZF-aware readers seek directly to a block
+/// without scanning the stream from the start.
+///
+/// Any FEXTRA subfield set on `options.gzip_header` is discarded: BGZF
+/// blocks carry only the BC subfield, so the header's own `extra` is
+/// overwritten rather than extended.
+pub fn bgzf_compress<R, W>(options: &Options, mut in_data: R, mut out: W) -> io::Result<()>
+    where R: Read, W: Write
+{
+    loop {
+        let mut chunk = Vec::with_capacity(BGZF_MAX_BLOCK_SIZE);
+        try!(in_data.by_ref().take(BGZF_MAX_BLOCK_SIZE as u64).read_to_end(&mut chunk));
+        if chunk.is_empty() {
+            break;
+        }
+
+        try!(write_bgzf_block(options, &chunk, out.by_ref()));
+    }
+
+    out.write_all(BGZF_EOF)
+}
+
+/// Like `bgzf_compress`, but also returns the virtual offset (see
+/// `bgzf_virtual_offset`) of the start of each block, so callers can build
+/// a companion index for random access into the compressed stream.
+pub fn bgzf_compress_indexed<R, W>(options: &Options, mut in_data: R, mut out: W) -> io::Result<Vec<u64>>
+    where R: Read, W: Write
+{
+    let mut block_offsets = Vec::new();
+    let mut compressed_offset = 0u64;
+
+    loop {
+        let mut chunk = Vec::with_capacity(BGZF_MAX_BLOCK_SIZE);
+        try!(in_data.by_ref().take(BGZF_MAX_BLOCK_SIZE as u64).read_to_end(&mut chunk));
+        if chunk.is_empty() {
+            break;
+        }
+
+        block_offsets.push(bgzf_virtual_offset(compressed_offset, 0));
+        compressed_offset += try!(write_bgzf_block(options, &chunk, out.by_ref())) as u64;
+    }
+
+    try!(out.write_all(BGZF_EOF));
+    Ok(block_offsets)
+}
+
+/// Test-only hook letting tests force a specific chunk's worker to panic,
+/// so `gzip_compress_parallel`'s panic-handling path can be exercised
+/// without depending on a real panic deep in the deflate passes.
+#[cfg(test)]
+mod fault_injection {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Mutex, MutexGuard};
+
+    static PANIC_ON_CHUNK: AtomicUsize = AtomicUsize::new(::std::usize::MAX);
+
+    /// `PANIC_ON_CHUNK` is process-wide, so any test that arms it would
+    /// otherwise leak a spurious panic into every other `gzip_compress_parallel`
+    /// call running concurrently on cargo test's other threads. Tests that
+    /// call `arm` must hold this lock for the duration of their call into
+    /// `gzip_compress_parallel`.
+    static TEST_LOCK: Mutex<()> = Mutex::new(());
+
+    pub fn lock() -> MutexGuard<'static, ()> {
+        TEST_LOCK.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    pub fn maybe_panic(index: usize) {
+        if PANIC_ON_CHUNK.load(Ordering::SeqCst) == index {
+            panic!("fault_injection: injected panic for chunk {}", index);
+        }
+    }
+
+    /// Arms a panic for `index`, or disarms any armed panic with `None`.
+    pub fn arm(index: Option<usize>) {
+        PANIC_ON_CHUNK.store(index.unwrap_or(::std::usize::MAX), Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crc::{Hasher32, crc32};
+    use flate2::read::MultiGzDecoder;
+
+    #[test]
+    fn filename_rejects_nul_and_non_latin1() {
+        let header = GzipHeader::default();
+        assert!(header.clone().filename("ok.txt").is_ok());
+        assert!(header.clone().filename("bad\0name").is_err());
+        // U+3000 is outside Latin-1 and its low byte is 0x00, so truncating
+        // it would silently embed a NUL mid-field.
+        assert!(header.filename("\u{3000}").is_err());
+    }
+
+    #[test]
+    fn header_hcrc_matches_recomputed_crc16() {
+        let header = GzipHeader::default().hcrc(true);
+        let bytes = header_bytes(&header);
+        let crc16_offset = bytes.len() - 2;
+
+        let mut digest = crc32::Digest::new(crc32::IEEE);
+        digest.write(&bytes[..crc16_offset]);
+        let expected = digest.sum32() as u16;
+        let actual = bytes[crc16_offset] as u16 | ((bytes[crc16_offset + 1] as u16) << 8);
+
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn bgzf_block_bsize_matches_actual_length() {
+        let options = Options::default();
+        // Incompressible filler, sized to the max block so the real block
+        // length sits close to the 16-bit BSIZE field's limit.
+        let mut state = 0x9E3779B9u32;
+        let data: Vec<u8> = (0..BGZF_MAX_BLOCK_SIZE)
+            .map(|_| {
+                // xorshift32
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                (state >> 24) as u8
+            })
+            .collect();
+
+        let mut out = Vec::new();
+        bgzf_compress(&options, &data[..], &mut out).unwrap();
+
+        // BSIZE lives at bytes 16..18: ID1,ID2,CM,FLG(4) + MTIME(4) +
+        // XFL,OS(2) + XLEN(2) + SI1,SI2,SLEN(4), then BSIZE(2).
+        let bsize = out[16] as usize | ((out[17] as usize) << 8);
+        let block_len = out.len() - BGZF_EOF.len();
+
+        assert_eq!(bsize, block_len - 1);
+    }
+
+    #[test]
+    fn bgzf_block_errors_instead_of_overflowing_bsize() {
+        let mut options = Options::default();
+        // An oversized filename inflates the header past the point where
+        // `total_len - 1` still fits in BSIZE's 16 bits.
+        options.gzip_header = GzipHeader::default().filename("a".repeat(70_000)).unwrap();
+
+        let data = vec![0u8; 10];
+        let mut out = Vec::new();
+
+        assert!(bgzf_compress(&options, &data[..], &mut out).is_err());
+    }
+
+    #[test]
+    fn extra_rejects_subfields_that_overflow_xlen() {
+        let header = GzipHeader::default();
+        assert!(header.clone().extra(vec![0; 65535]).is_ok());
+        assert!(header.extra(vec![0; 65536]).is_err());
+    }
+
+    #[test]
+    fn gzip_compress_parallel_round_trips_multi_chunk_input() {
+        let _guard = fault_injection::lock();
+        let mut options = Options::default();
+        options.chunk_size = 37;
+        let data: Vec<u8> = (0..500).map(|i| (i % 251) as u8).collect();
+
+        let mut out = Vec::new();
+        gzip_compress_parallel(&options, &data, &mut out).unwrap();
+
+        // MultiGzDecoder reads concatenated gzip members transparently, so
+        // this also confirms the members were written in the right order.
+        let mut decoded = Vec::new();
+        MultiGzDecoder::new(&out[..]).read_to_end(&mut decoded).unwrap();
+
+        assert_eq!(decoded, data);
+    }
+
+    #[test]
+    fn gzip_compress_parallel_panicking_worker_returns_err_not_hang() {
+        let _guard = fault_injection::lock();
+        let mut options = Options::default();
+        options.chunk_size = 8;
+        let data = vec![0u8; 40]; // 5 chunks of 8 bytes each
+
+        fault_injection::arm(Some(2));
+        let result = gzip_compress_parallel(&options, &data, &mut Vec::new());
+        fault_injection::arm(None);
+
+        assert!(result.is_err());
+    }
+}
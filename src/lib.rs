@@ -5,6 +5,10 @@ extern crate byteorder;
 extern crate crc;
 extern crate typed_arena;
 extern crate iter_read;
+extern crate num_cpus;
+extern crate threadpool;
+#[cfg(test)]
+extern crate flate2;
 
 mod iter;
 mod blocksplitter;
@@ -23,10 +27,13 @@ mod zlib;
 use std::io::{self, Read, Seek, SeekFrom, Write};
 
 use deflate::{deflate, BlockType};
-use gzip::gzip_compress;
+use gzip::{gzip_compress, gzip_compress_parallel, bgzf_compress};
 use zlib::zlib_compress;
 
+pub use gzip::{GzipHeader, bgzf_compress_indexed, bgzf_virtual_offset};
+
 /// Options used throughout the program.
+#[derive(Clone)]
 pub struct Options {
   /* Whether to print output */
   pub verbose: bool,
@@ -43,6 +50,14 @@ pub struct Options {
   extreme results that hurt compression on some files). Default value: 15.
   */
   blocksplittingmax: i32,
+  /* The gzip header fields to emit when compressing to Format::Gzip. */
+  pub gzip_header: GzipHeader,
+  /*
+  Size in bytes of the uncompressed chunks that compress_parallel splits
+  input into. Each chunk becomes an independent gzip member compressed on
+  its own worker thread.
+  */
+  pub chunk_size: u64,
 }
 
 impl Default for Options {
@@ -52,14 +67,74 @@ impl Default for Options {
             verbose_more: false,
             numiterations: 15,
             blocksplittingmax: 15,
+            gzip_header: GzipHeader::default(),
+            chunk_size: 1024 * 1024,
         }
     }
 }
 
+impl Options {
+    /// Starts building an `Options` with explicit control over the
+    /// iteration count and block splitting, for callers who don't want one
+    /// of the named presets.
+    pub fn builder() -> OptionsBuilder {
+        OptionsBuilder::default()
+    }
+
+    /// A preset tuned for speed on large, multi-megabyte inputs: fewer
+    /// reruns of the forward/backward LZ77 optimization pass and less
+    /// aggressive block splitting.
+    pub fn fast() -> Options {
+        Options::builder().iterations(5).block_splitting_max(8).build()
+    }
+
+    /// A preset tuned for maximum compression ratio at the cost of speed.
+    pub fn max() -> Options {
+        Options::builder().iterations(50).block_splitting_max(15).build()
+    }
+}
+
+/// Builder for `Options`, so library users can tune `numiterations` and
+/// `blocksplittingmax` without forking the struct. Unset fields keep
+/// `Options::default()`'s values.
+#[derive(Clone)]
+pub struct OptionsBuilder {
+    options: Options,
+}
+
+impl Default for OptionsBuilder {
+    fn default() -> OptionsBuilder {
+        OptionsBuilder { options: Options::default() }
+    }
+}
+
+impl OptionsBuilder {
+    /// Sets the maximum amount of times to rerun the forward and backward
+    /// pass to optimize LZ77 compression cost.
+    pub fn iterations(mut self, iterations: i32) -> OptionsBuilder {
+        self.options.numiterations = iterations;
+        self
+    }
+
+    /// Sets the maximum amount of blocks to split into (0 for unlimited).
+    pub fn block_splitting_max(mut self, blocksplittingmax: i32) -> OptionsBuilder {
+        self.options.blocksplittingmax = blocksplittingmax;
+        self
+    }
+
+    pub fn build(self) -> Options {
+        self.options
+    }
+}
+
 pub enum Format {
     Gzip,
     Zlib,
     Deflate,
+    /// Blocked GZip Format: a sequence of small, independent gzip members
+    /// suitable for random-access decompression. See `bgzf_compress_indexed`
+    /// for building a companion seek index.
+    Bgzf,
 }
 
 pub fn compress_seekable<R, W>(options: &Options, output_type: &Format, mut in_data: R, out: W) -> io::Result<()>
@@ -82,5 +157,26 @@ pub fn compress<R, W>(options: &Options, output_type: &Format, in_data: R, insiz
         Format::Gzip => gzip_compress(options, in_data, insize, out),
         Format::Zlib => zlib_compress(options, in_data, insize, out),
         Format::Deflate => deflate(options, BlockType::Dynamic, in_data, insize, out),
+        Format::Bgzf => bgzf_compress(options, in_data, out),
+    }
+}
+
+/// Compresses `in_data` on a thread pool sized to the CPU count, splitting
+/// it into `options.chunk_size`-sized chunks that are each compressed as an
+/// independent, complete gzip member. The members are concatenated to `out`
+/// in input order, which per RFC 1952 is read transparently by standard
+/// gzip decoders as a single stream.
+///
+/// Only `Format::Gzip` is supported, since multi-member concatenation is a
+/// gzip-specific trick; zlib and raw deflate streams have no equivalent.
+pub fn compress_parallel<W>(options: &Options, output_type: &Format, in_data: &[u8], out: W) -> io::Result<()>
+    where W: Write
+{
+    match *output_type {
+        Format::Gzip => gzip_compress_parallel(options, in_data, out),
+        Format::Zlib | Format::Deflate | Format::Bgzf => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "parallel compression is only supported for Format::Gzip",
+        )),
     }
 }